@@ -1,5 +1,7 @@
 use std::io::{self, BufRead};
+use std::sync::Mutex;
 
+use crossbeam::channel;
 use log::{error, warn};
 
 use crate::collapse::common::{self, Occurrences};
@@ -21,6 +23,14 @@ static IGNORE_SYMBOLS: &[&str] = &[
     "semaphore_wait_trap",
 ];
 
+// Modules that make up the kernel itself, as opposed to user-space system frameworks or the
+// profiled application's own code.
+static KERNEL_MODULE_PREFIXES: &[&str] = &["libsystem_kernel", "com.apple.kernel"];
+
+// User-space modules shipped by Apple as part of the OS (frameworks, system libraries), as
+// opposed to the profiled application's own code.
+static SYSTEM_MODULE_PREFIXES: &[&str] = &["libsystem_", "libdyld", "libobjc", "com.apple."];
+
 // The call graph begins after this line.
 static START_LINE: &str = "Call graph:";
 
@@ -28,16 +38,67 @@ static START_LINE: &str = "Call graph:";
 // We know we're done when we get to this line.
 static END_LINE: &str = "Total number in stack";
 
+// Minimum number of input bytes per worker thread before the parallel path pays off; inputs
+// smaller than `nthreads * MIN_BYTES_PER_THREAD` are collapsed on the current thread instead.
+const MIN_BYTES_PER_THREAD: usize = 16 * 1024;
+
+// Best-effort extraction of a message from a caught worker panic, for inclusion in the
+// `io::Error` that `collapse_multi_threaded` reports instead of re-panicking.
+fn describe_panic(payload: &(dyn std::any::Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "<non-string panic payload>"
+    }
+}
+
 /// `sample` folder configuration options.
 #[derive(Clone, Debug)]
 pub struct Options {
     /// Don't include modules with function names. Default is `false`.
     pub no_modules: bool,
+
+    /// Don't skip stacks ending in a "waiting" symbol (see `IGNORE_SYMBOLS`). Default is `false`.
+    pub include_idle: bool,
+
+    /// Additional leaf symbols to treat as "waiting", merged with the built-in
+    /// `IGNORE_SYMBOLS` set. Default is empty.
+    pub ignore_symbols: Vec<String>,
+
+    /// Keep the thread identifier (or thread name, if `sample` printed one) as the root frame of
+    /// every collapsed stack, so that per-thread activity can be told apart in the flame graph.
+    /// Default is `false`.
+    pub thread_root: bool,
+
+    /// Append a `_[k]` suffix to functions from kernel modules (e.g. `libsystem_kernel.dylib`),
+    /// mirroring the perf collapser's `annotate_kernel`. Default is `false`.
+    pub annotate_kernel: bool,
+
+    /// Append a `_[s]` suffix to functions from other system modules (Apple frameworks and
+    /// libraries), to tell system time apart from application time. Default is `false`.
+    pub annotate_system: bool,
+
+    /// The number of threads to use for collapsing a large input in parallel. Each top-level
+    /// `Thread_xxxx` block is an independent subtree, so blocks are handed out to a pool of this
+    /// many workers and reduced afterwards. Inputs smaller than `nthreads *
+    /// MIN_BYTES_PER_THREAD` are still collapsed on the current thread. Default is the number
+    /// of logical CPUs.
+    pub nthreads: usize,
 }
 
 impl Default for Options {
     fn default() -> Self {
-        Self { no_modules: false }
+        Self {
+            no_modules: false,
+            include_idle: false,
+            ignore_symbols: Vec::default(),
+            thread_root: false,
+            annotate_kernel: false,
+            annotate_system: false,
+            nthreads: num_cpus::get(),
+        }
     }
 }
 
@@ -53,6 +114,16 @@ pub struct Folder {
     /// Function on the stack in this entry thus far.
     stack: Vec<String>,
 
+    /// Label of the thread whose block is currently being processed, used as the root frame
+    /// when `opt.thread_root` is set.
+    current_thread: Option<String>,
+
+    /// The current leaf's function name as it appeared in the call graph, before `annotate`
+    /// tagged it with a `_[k]`/`_[s]` suffix. Kept around so `write_stack` can match
+    /// `IGNORE_SYMBOLS`/`opt.ignore_symbols` against the name `sample` actually printed, not
+    /// against a string that annotation has since appended a suffix to.
+    current_raw_func: Option<String>,
+
     opt: Options,
 }
 
@@ -61,6 +132,8 @@ impl Default for Folder {
         Self {
             current_samples: 0,
             stack: Vec::default(),
+            current_thread: None,
+            current_raw_func: None,
             opt: Options::default(),
         }
     }
@@ -85,26 +158,38 @@ impl Collapse for Folder {
             }
         }
 
-        // Process the data...
-        let mut occurrences = Occurrences::new(1);
+        // Buffer up the call graph so we know its size before deciding whether to collapse it
+        // serially or hand its independent thread blocks out to a worker pool. This is a
+        // deliberate trade-off: the serial-vs-parallel decision needs the *total* byte count,
+        // which isn't known until the whole call graph has been read, so every invocation pays
+        // this extra allocation and copy even when the input turns out to be too small to
+        // parallelize. That cost is one `String` per line, which is small next to the per-line
+        // parsing `on_line`/`write_stack` already do.
+        let mut lines = Vec::new();
+        let mut nbytes = 0;
         loop {
             line.clear();
-            if reader.read_line(&mut line)? == 0 {
+            let nread = reader.read_line(&mut line)?;
+            if nread == 0 {
                 warn!("File ended before end of call graph");
-                self.write_stack(&mut occurrences);
                 break;
             }
-            let line = line.trim_end();
+            nbytes += nread;
+            let line = line.trim_end().to_string();
             if line.is_empty() {
                 continue;
-            } else if line.starts_with("    ") {
-                self.on_line(line, &mut occurrences);
             } else if line.starts_with(END_LINE) {
-                self.write_stack(&mut occurrences);
                 break;
-            } else {
-                error!("Stack line doesn't start with 4 spaces:\n{}", line);
             }
+            lines.push(line);
+        }
+
+        // Process the data...
+        let mut occurrences = Occurrences::new(1);
+        if self.opt.nthreads > 1 && nbytes >= self.opt.nthreads * MIN_BYTES_PER_THREAD {
+            self.collapse_multi_threaded(lines, &mut occurrences)?;
+        } else {
+            self.collapse_single_threaded(lines, &mut occurrences);
         }
 
         // Write the results...
@@ -113,6 +198,8 @@ impl Collapse for Folder {
         // Reset the state...
         self.current_samples = 0;
         self.stack.clear();
+        self.current_thread = None;
+        self.current_raw_func = None;
         Ok(())
     }
 
@@ -152,6 +239,138 @@ impl From<Options> for Folder {
 }
 
 impl Folder {
+    // Whether a flush is safe to perform right now. A thread may have no sampled child lines at
+    // all (a valid call-graph shape), and the first header has nothing before it; flushing then
+    // would write a bogus entry keyed on just the thread label (or on nothing), carrying over
+    // stale `current_samples` from whatever came before. `current_thread.is_some()` isn't a
+    // usable guard on its own -- it's true for every header after the first -- so this checks
+    // the stack instead.
+    fn should_flush(&self) -> bool {
+        !self.stack.is_empty()
+    }
+
+    // Dispatch each buffered line to `on_line`/`write_stack` on the current thread. A top-level
+    // `Thread_xxxx` header ends the previous thread's stack and records the new thread's label,
+    // whether or not `thread_root` puts that label into the stack keys.
+    fn collapse_single_threaded(&mut self, lines: Vec<String>, occurrences: &mut Occurrences) {
+        for line in &lines {
+            if line.starts_with("    ") {
+                self.on_line(line, occurrences);
+            } else if Self::thread_label(line).is_some() {
+                if self.should_flush() {
+                    self.write_stack(occurrences);
+                }
+                self.stack.clear();
+                self.current_thread = Self::thread_label(line);
+            } else {
+                error!("Stack line doesn't start with 4 spaces:\n{}", line);
+            }
+        }
+        if self.should_flush() {
+            self.write_stack(occurrences);
+        }
+    }
+
+    // Split the call graph into its independent top-level `Thread_xxxx` blocks, preserving
+    // order. Any lines preceding the first recognized thread header are kept as their own
+    // leading block.
+    fn split_into_blocks(lines: Vec<String>) -> Vec<Vec<String>> {
+        let mut blocks: Vec<Vec<String>> = Vec::new();
+        for line in lines {
+            if !line.starts_with("    ") && Self::thread_label(&line).is_some() {
+                blocks.push(vec![line]);
+            } else if let Some(block) = blocks.last_mut() {
+                block.push(line);
+            } else {
+                blocks.push(vec![line]);
+            }
+        }
+        blocks
+    }
+
+    // Reentrant helper that collapses a single thread block in its own `Folder` and its own,
+    // unlocked `Occurrences` map, so it can run on any worker without sharing
+    // `current_samples`/`stack` state -- or a lock -- with other blocks in flight.
+    fn collapse_block(opt: &Options, block: &[String]) -> Occurrences {
+        let mut folder = Folder::from(opt.clone());
+        let mut local = Occurrences::new(1);
+        for line in block {
+            if line.starts_with("    ") {
+                folder.on_line(line, &mut local);
+            } else if Self::thread_label(line).is_some() {
+                if folder.should_flush() {
+                    folder.write_stack(&mut local);
+                }
+                folder.stack.clear();
+                folder.current_thread = Self::thread_label(line);
+            } else {
+                error!("Stack line doesn't start with 4 spaces:\n{}", line);
+            }
+        }
+        if folder.should_flush() {
+            folder.write_stack(&mut local);
+        }
+        local
+    }
+
+    // Hand the call graph's independent thread blocks out to a pool of `opt.nthreads` workers
+    // pulling off a shared channel. Each worker collapses its block into a local `Occurrences`
+    // map with no locking at all, then takes the shared lock exactly once per block -- not once
+    // per line -- to fold its counts into `occurrences`, summing counts for identical stack keys.
+    //
+    // A panicking worker is surfaced as an `io::Error` rather than taken as `unwrap()`/`expect()`
+    // license to abort the process: this is new concurrency machinery, and a bug in one block's
+    // parsing shouldn't be able to take down a caller that would otherwise have handled a normal
+    // `io::Result` error. Other workers recover the lock instead of also panicking on it: the
+    // poisoning is a side effect of the first panic, not a second independent failure, and the
+    // first panic is what gets reported below.
+    fn collapse_multi_threaded(
+        &self,
+        lines: Vec<String>,
+        occurrences: &mut Occurrences,
+    ) -> io::Result<()> {
+        let blocks = Self::split_into_blocks(lines);
+        let (tx, rx) = channel::unbounded();
+        for block in blocks {
+            tx.send(block).expect("rx outlives tx within this scope");
+        }
+        drop(tx);
+
+        let shared = Mutex::new(Occurrences::new(1));
+        let result = crossbeam::thread::scope(|scope| {
+            for _ in 0..self.opt.nthreads {
+                let rx = rx.clone();
+                let opt = &self.opt;
+                let shared = &shared;
+                scope.spawn(move |_| {
+                    while let Ok(block) = rx.recv() {
+                        let local = Self::collapse_block(opt, &block);
+                        let mut shared =
+                            shared.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                        for (key, count) in local {
+                            shared.insert_or_add(key, count);
+                        }
+                    }
+                });
+            }
+        });
+
+        result.map_err(|panic| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "a sample collapse worker thread panicked: {}",
+                    describe_panic(&*panic)
+                ),
+            )
+        })?;
+
+        *occurrences = shared
+            .into_inner()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        Ok(())
+    }
+
     fn line_parts<'a>(&self, line: &'a str) -> Option<(&'a str, &'a str, &'a str)> {
         let mut line = line.trim_start().splitn(2, ' ');
         let time = line.next()?.trim_end();
@@ -163,26 +382,76 @@ impl Folder {
         }
         .trim_end();
 
+        // Modules are shown with "(in libfoo.dylib)" or "(in AppKit)".
+        // We've arleady split on "(in " above.
+        //
+        // Extract this regardless of `no_modules`: `annotate` needs to classify kernel/system
+        // frames even when the module prefix itself is being stripped from the displayed
+        // function name. Whether the prefix is actually shown is decided separately, in
+        // `on_line`.
         let mut module = "";
-        if !self.opt.no_modules {
-            // Modules are shown with "(in libfoo.dylib)" or "(in AppKit)".
-            // We've arleady split on "(in " above.
-            let mut line = line.rsplitn(2, "(in ");
-            if let Some(line) = line.next() {
-                if let Some(close) = line.find(')') {
-                    module = &line[..close];
-                }
+        let mut line = line.rsplitn(2, "(in ");
+        if let Some(line) = line.next() {
+            if let Some(close) = line.find(')') {
+                module = &line[..close];
+            }
 
-                // Remove ".dylib", since it adds no value.
-                if module.ends_with(".dylib") {
-                    module = &module[..module.len() - 6]
-                }
+            // Remove ".dylib", since it adds no value.
+            if module.ends_with(".dylib") {
+                module = &module[..module.len() - 6]
             }
         }
 
         Some((time, func, module))
     }
 
+    // Handle depth-0 thread lines of the form:
+    //
+    // 5130 Thread_8749954
+    //
+    // or, when `sample` has a human-readable name for the thread:
+    //
+    // 5130 Thread_8749954   DispatchQueue-1: <com.apple.main-thread>  (serial)
+    //
+    // Returns the thread name if present, otherwise falls back to the thread identifier.
+    fn thread_label(line: &str) -> Option<String> {
+        let mut parts = line.trim_start().splitn(2, ' ');
+        let _samples = parts.next()?;
+        let rest = parts.next()?.trim_start();
+        if !rest.starts_with("Thread_") {
+            return None;
+        }
+        let mut rest = rest.splitn(2, char::is_whitespace);
+        let tid = rest.next()?;
+        let name = rest.next().map(str::trim).filter(|name| !name.is_empty());
+        Some(name.unwrap_or(tid).to_string())
+    }
+
+    // Tag `func` with a suffix identifying whether it came from the kernel or another system
+    // module, based on its owning `module`, per `opt.annotate_kernel`/`opt.annotate_system`.
+    fn annotate(&self, func: &str, module: &str) -> String {
+        // `SYSTEM_MODULE_PREFIXES` entries (e.g. "libsystem_", "com.apple.") are themselves
+        // prefixes of `KERNEL_MODULE_PREFIXES` entries (e.g. "libsystem_kernel"), so whether a
+        // module is a kernel module has to be decided unconditionally -- not only when
+        // `annotate_kernel` is set -- or a kernel frame gets mislabeled as a system frame
+        // whenever `annotate_system` is on by itself.
+        let is_kernel = KERNEL_MODULE_PREFIXES
+            .iter()
+            .any(|prefix| module.starts_with(prefix));
+        if self.opt.annotate_kernel && is_kernel {
+            format!("{}_[k]", func)
+        } else if self.opt.annotate_system
+            && !is_kernel
+            && SYSTEM_MODULE_PREFIXES
+                .iter()
+                .any(|prefix| module.starts_with(prefix))
+        {
+            format!("{}_[s]", func)
+        } else {
+            func.to_string()
+        }
+    }
+
     fn is_indent_char(c: char) -> bool {
         c == ' ' || c == '+' || c == '|' || c == ':' || c == '!'
     }
@@ -226,8 +495,10 @@ impl Folder {
                     self.current_samples = samples;
                     // sample doesn't properly demangle Rust symbols, so fix those.
                     let func = common::fix_partially_demangled_rust_symbol(func);
-                    if module.is_empty() {
-                        self.stack.push(func.to_string());
+                    self.current_raw_func = Some(func.as_ref().to_string());
+                    let func = self.annotate(func.as_ref(), module);
+                    if self.opt.no_modules || module.is_empty() {
+                        self.stack.push(func);
                     } else {
                         self.stack.push(format!("{}`{}", module, func));
                     }
@@ -243,15 +514,32 @@ impl Folder {
     }
 
     fn write_stack(&self, occurrences: &mut Occurrences) {
-        if let Some(func) = self.stack.last() {
-            for symbol in IGNORE_SYMBOLS {
-                if func.ends_with(symbol) {
+        if !self.opt.include_idle {
+            // Match against the pre-annotation function name: `annotate` may have appended a
+            // `_[k]`/`_[s]` suffix to `self.stack`'s leaf entry, and almost every default
+            // `IGNORE_SYMBOLS` entry is itself a kernel trap, so matching the annotated name
+            // would silently defeat idle-thread filtering whenever `annotate_kernel` is on.
+            if let Some(func) = &self.current_raw_func {
+                let ignored = IGNORE_SYMBOLS
+                    .iter()
+                    .map(|symbol| symbol as &str)
+                    .chain(self.opt.ignore_symbols.iter().map(|symbol| symbol.as_str()))
+                    .any(|symbol| func.ends_with(symbol));
+                if ignored {
                     // Don't write out stacks with ignored symbols
                     return;
                 }
             }
         }
         let mut key = String::new();
+        if self.opt.thread_root {
+            if let Some(thread) = &self.current_thread {
+                key.push_str(thread);
+                if !self.stack.is_empty() {
+                    key.push(';');
+                }
+            }
+        }
         for (i, frame) in self.stack.iter().enumerate() {
             if i > 0 {
                 key.push(';');
@@ -261,3 +549,234 @@ impl Folder {
         occurrences.insert(key, self.current_samples);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A `sample` call graph made up of `nblocks` independent thread blocks that all collapse to
+    // the identical stack key. Padded out with enough repeated blocks to push the input past
+    // `nthreads * MIN_BYTES_PER_THREAD`, so the parallel path actually engages.
+    fn repeated_stack_input(nblocks: usize) -> String {
+        let mut input = String::from("Call graph:\n");
+        for i in 0..nblocks {
+            input.push_str(&format!("  2 Thread_{}\n", i));
+            input.push_str("    + 2 start_wqthread  (in libsystem_pthread.dylib) + 1\n");
+            input.push_str("    +   2 work  (in MyApp) + 2\n");
+        }
+        input.push_str("Total number in stack: 1\n");
+        input
+    }
+
+    fn collapse_with(opt: Options, input: &str) -> String {
+        let mut folder = Folder::from(opt);
+        let mut output = Vec::new();
+        folder.collapse(input.as_bytes(), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn parallel_reduction_matches_serial() {
+        let nthreads = 4;
+        // Each block is a little over 100 bytes; sizing for 70 bytes a block makes sure we clear
+        // `nthreads * MIN_BYTES_PER_THREAD` with room to spare.
+        let nblocks = (nthreads * MIN_BYTES_PER_THREAD) / 70 + 10;
+        let input = repeated_stack_input(nblocks);
+
+        let serial = collapse_with(
+            Options {
+                nthreads: 1,
+                ..Options::default()
+            },
+            &input,
+        );
+        let parallel = collapse_with(
+            Options {
+                nthreads,
+                ..Options::default()
+            },
+            &input,
+        );
+
+        assert_eq!(serial, parallel);
+
+        // Every block shares the same stack key, so the serial path's single merged entry
+        // should have summed all `nblocks` blocks' counts -- not just the last one's.
+        let lines: Vec<&str> = serial.lines().collect();
+        assert_eq!(lines.len(), 1);
+        let count: usize = lines[0].rsplit(' ').next().unwrap().parse().unwrap();
+        assert_eq!(count, 2 * nblocks);
+    }
+
+    // A thread block with no sampled child lines at all -- a valid `sample` call-graph shape --
+    // sandwiched between two populated threads. Exercises both the header-triggered flush guard
+    // and the trailing flush directly via the private helpers, bypassing the byte-size gate that
+    // `Collapse::collapse` normally uses to pick between them.
+    fn empty_thread_block_lines() -> Vec<String> {
+        vec![
+            "  100 Thread_A".to_string(),
+            "    + 100 work_a  (in MyApp) + 1".to_string(),
+            "  1 Thread_B".to_string(),
+            "  50 Thread_C".to_string(),
+            "    + 50 work_c  (in MyApp) + 1".to_string(),
+        ]
+    }
+
+    #[test]
+    fn empty_thread_block_agrees_between_serial_and_parallel() {
+        let opt = Options {
+            thread_root: true,
+            nthreads: 4,
+            ..Options::default()
+        };
+
+        let mut serial_occ = Occurrences::new(1);
+        Folder::from(opt.clone())
+            .collapse_single_threaded(empty_thread_block_lines(), &mut serial_occ);
+        let mut serial_out = Vec::new();
+        serial_occ.write_and_clear(&mut serial_out).unwrap();
+        let serial = String::from_utf8(serial_out).unwrap();
+
+        let mut parallel_occ = Occurrences::new(1);
+        Folder::from(opt.clone())
+            .collapse_multi_threaded(empty_thread_block_lines(), &mut parallel_occ)
+            .unwrap();
+        let mut parallel_out = Vec::new();
+        parallel_occ.write_and_clear(&mut parallel_out).unwrap();
+        let parallel = String::from_utf8(parallel_out).unwrap();
+
+        assert_eq!(serial, parallel);
+        // The empty thread must not produce an entry of its own, in either path.
+        assert!(!serial.contains("Thread_B"));
+    }
+
+    #[test]
+    fn annotate_kernel_and_system_are_mutually_exclusive() {
+        let kernel_only = Folder::from(Options {
+            annotate_kernel: true,
+            ..Options::default()
+        });
+        assert_eq!(
+            kernel_only.annotate("__doworkq_kernreturn", "libsystem_kernel"),
+            "__doworkq_kernreturn_[k]"
+        );
+
+        // A kernel module must not be mislabeled as a system frame just because
+        // `annotate_system` is on by itself -- `SYSTEM_MODULE_PREFIXES` entries like
+        // "libsystem_" are themselves prefixes of kernel modules like "libsystem_kernel".
+        let system_only = Folder::from(Options {
+            annotate_system: true,
+            ..Options::default()
+        });
+        assert_eq!(
+            system_only.annotate("__doworkq_kernreturn", "libsystem_kernel"),
+            "__doworkq_kernreturn"
+        );
+        assert_eq!(
+            system_only.annotate("objc_msgSend", "libobjc"),
+            "objc_msgSend_[s]"
+        );
+
+        // With both flags on, a kernel module is still tagged `_[k]`, never `_[s]`.
+        let both = Folder::from(Options {
+            annotate_kernel: true,
+            annotate_system: true,
+            ..Options::default()
+        });
+        assert_eq!(
+            both.annotate("__doworkq_kernreturn", "libsystem_kernel"),
+            "__doworkq_kernreturn_[k]"
+        );
+    }
+
+    fn waiting_thread_input(leaf: &str) -> String {
+        format!(
+            "Call graph:\n  2 Thread_1\n    + 2 {}  (in MyApp) + 1\nTotal number in stack: 1\n",
+            leaf
+        )
+    }
+
+    #[test]
+    fn include_idle_keeps_a_built_in_waiting_symbol() {
+        let input = waiting_thread_input("mach_msg_trap");
+
+        let default = collapse_with(Options::default(), &input);
+        assert!(default.is_empty());
+
+        let idle = collapse_with(
+            Options {
+                include_idle: true,
+                ..Options::default()
+            },
+            &input,
+        );
+        assert!(idle.contains("mach_msg_trap"));
+    }
+
+    #[test]
+    fn ignore_symbols_merges_with_the_built_in_set() {
+        let input = waiting_thread_input("custom_futex_wait");
+
+        // Not a built-in waiting symbol, so it's kept by default.
+        let default = collapse_with(Options::default(), &input);
+        assert!(default.contains("custom_futex_wait"));
+
+        // Once added to `ignore_symbols`, it's dropped just like a built-in one.
+        let ignored = collapse_with(
+            Options {
+                ignore_symbols: vec!["custom_futex_wait".to_string()],
+                ..Options::default()
+            },
+            &input,
+        );
+        assert!(ignored.is_empty());
+    }
+
+    #[test]
+    fn annotate_kernel_does_not_defeat_idle_filtering() {
+        let input = "Call graph:\n  2 Thread_1\n    + 2 mach_msg_trap  (in libsystem_kernel.dylib) + 1\nTotal number in stack: 1\n";
+
+        // `annotate_kernel` tags the leaf with a `_[k]` suffix, but the ignore-symbol match must
+        // still see the un-suffixed name, or every default `IGNORE_SYMBOLS` entry -- almost all
+        // of them kernel traps -- stops matching and idle threads leak through by default.
+        let annotated = collapse_with(
+            Options {
+                annotate_kernel: true,
+                ..Options::default()
+            },
+            input,
+        );
+        assert!(annotated.is_empty());
+
+        let with_idle = collapse_with(
+            Options {
+                annotate_kernel: true,
+                include_idle: true,
+                ..Options::default()
+            },
+            input,
+        );
+        assert!(with_idle.contains("mach_msg_trap_[k]"));
+    }
+
+    #[test]
+    fn thread_root_prefixes_the_stack_key_with_the_thread_label() {
+        let input = "Call graph:\n  2 Thread_1   DispatchQueue-1: <com.apple.main-thread>  (serial)\n    + 2 work  (in MyApp) + 1\nTotal number in stack: 1\n";
+
+        // Without `thread_root`, the thread identity doesn't appear in the stack key at all.
+        let without = collapse_with(Options::default(), input);
+        assert!(!without.contains("DispatchQueue-1"));
+        assert!(without.starts_with("MyApp`work "));
+
+        // With it, the human-readable thread name (preferred over the raw `Thread_xxxx` id) is
+        // the root frame of the collapsed stack.
+        let with = collapse_with(
+            Options {
+                thread_root: true,
+                ..Options::default()
+            },
+            input,
+        );
+        assert!(with.starts_with("DispatchQueue-1: <com.apple.main-thread>  (serial);MyApp`work "));
+    }
+}